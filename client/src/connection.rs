@@ -1,39 +1,327 @@
 use crate::session::generate_run_id;
 use crate::wandb_internal;
-use byteorder::{LittleEndian, WriteBytesExt};
+use codec::FrameCodec;
 use prost::Message;
 use std::{
-    collections::HashMap,
-    io::{BufWriter, Write},
-    net::TcpStream,
-    sync::mpsc::{channel, Sender},
+    collections::{HashMap, VecDeque},
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
 };
 
-#[repr(C)]
-struct Header {
-    magic: u8,
-    data_length: u32,
+/// Tunables for connection liveness handling, passed to [`Connection::new`].
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Applied to both the read and write half of the socket via
+    /// `TcpStream::set_read_timeout`/`set_write_timeout`. A blocked
+    /// read/write longer than this is treated as a dead peer.
+    pub read_timeout: Option<Duration>,
+    /// How often a keepalive frame is sent while the link is otherwise
+    /// idle.
+    pub keepalive_interval: Duration,
+    /// How many times to retry `TcpStream::connect` to the same peer
+    /// address before giving up on a dead connection.
+    pub max_reconnect_attempts: u32,
+    /// How long `send_and_recv_message` waits for a response before
+    /// failing with `ConnectionError::MailboxTimeout`. Guards against a
+    /// peer that stays connected but never replies, which no socket-level
+    /// timeout or reconnect would otherwise catch.
+    pub mailbox_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            read_timeout: Some(Duration::from_secs(30)),
+            keepalive_interval: Duration::from_secs(10),
+            max_reconnect_attempts: 5,
+            mailbox_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Errors surfaced by [`Connection`] instead of panicking. The background
+/// `recv` thread converts any of these into a best-effort `Err` delivered
+/// through every outstanding mailbox sender, so a blocked
+/// `send_and_recv_message` caller unblocks with an error rather than the
+/// thread panicking out from under it.
+#[derive(Debug, Clone)]
+pub enum ConnectionError {
+    /// A read, write, or connect call on the socket failed.
+    Io(String),
+    /// The frame header's magic byte wasn't `b'W'`.
+    BadMagic { got: u8 },
+    /// `ServerResponse::decode` failed on a reassembled message body.
+    Decode(String),
+    /// The peer closed the connection partway through a frame.
+    ShortRead,
+    /// The peer closed the connection cleanly, between frames.
+    PeerClosed,
+    /// `send_and_recv_message` timed out waiting for a response.
+    MailboxTimeout,
+    /// The mailbox channel was dropped before a result arrived.
+    ChannelClosed,
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::Io(msg) => write!(f, "I/O error: {}", msg),
+            ConnectionError::BadMagic { got } => {
+                write!(f, "bad magic byte: expected {:#04x} ('W'), got {:#04x}", b'W', got)
+            }
+            ConnectionError::Decode(msg) => write!(f, "failed to decode message: {}", msg),
+            ConnectionError::ShortRead => write!(f, "connection closed mid-frame"),
+            ConnectionError::PeerClosed => write!(f, "peer closed the connection"),
+            ConnectionError::MailboxTimeout => write!(f, "timed out waiting for a response"),
+            ConnectionError::ChannelClosed => {
+                write!(f, "response channel closed before a result arrived")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e.to_string())
+    }
+}
+
+impl From<prost::DecodeError> for ConnectionError {
+    fn from(e: prost::DecodeError) -> Self {
+        ConnectionError::Decode(e.to_string())
+    }
+}
+
+/// How urgently an outgoing message's frames should be written relative to
+/// other messages queued at the same time. Higher-priority streams are
+/// preferred by the writer thread, but lower-priority streams are never
+/// starved outright; see [`PendingWrites::pop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestPriority {
+    High,
+    Normal,
+    Low,
+}
+
+/// Encoded frames waiting to be written to the socket, bucketed by
+/// priority. The dedicated writer thread pulls from here, interleaving
+/// chunks of whichever messages are in flight.
+struct PendingWrites {
+    high: VecDeque<Vec<u8>>,
+    normal: VecDeque<Vec<u8>>,
+    low: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+impl PendingWrites {
+    fn new() -> Self {
+        PendingWrites {
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+            low: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty() && self.low.is_empty()
+    }
+
+    fn push(&mut self, priority: RequestPriority, frame: Vec<u8>) {
+        match priority {
+            RequestPriority::High => self.high.push_back(frame),
+            RequestPriority::Normal => self.normal.push_back(frame),
+            RequestPriority::Low => self.low.push_back(frame),
+        }
+    }
+
+    /// Pops the next frame to write. `tick` is a monotonically increasing
+    /// counter the caller bumps on every call: every 8th tick drains `low`
+    /// first, the other ticks divisible by 4 drain `normal` first, and the
+    /// remaining ticks drain `high` first, so a steady stream of
+    /// high-priority traffic can't starve lower-priority streams out
+    /// entirely.
+    fn pop(&mut self, tick: u64) -> Option<Vec<u8>> {
+        let order: [&mut VecDeque<Vec<u8>>; 3] = if tick % 8 == 0 {
+            [&mut self.low, &mut self.normal, &mut self.high]
+        } else if tick % 4 == 0 {
+            [&mut self.normal, &mut self.high, &mut self.low]
+        } else {
+            [&mut self.high, &mut self.normal, &mut self.low]
+        };
+        order.into_iter().find_map(|q| q.pop_front())
+    }
 }
 
 pub struct Connection {
-    pub stream: TcpStream,
+    /// Shared so a dead-peer reconnect can swap in a fresh `TcpStream` that
+    /// the reader, writer, and keepalive threads all pick up on their next
+    /// lock.
+    pub stream: Arc<Mutex<TcpStream>>,
+    peer_addr: SocketAddr,
+    config: ConnectionConfig,
     // hashmap string -> channel
-    pub handles: HashMap<String, Sender<wandb_internal::Result>>,
+    /// Shared so the mailbox slots inserted by `send_and_recv_message`
+    /// callers are visible to the background `recv` thread's own clone of
+    /// `Connection`, rather than each clone mutating its own independent
+    /// copy.
+    pub handles: Arc<Mutex<MailboxHandles>>,
+    /// Transport-agnostic framing/reassembly state, see [`codec::FrameCodec`].
+    codec: FrameCodec,
+    /// Source of unique stream ids for outgoing messages, so the peer can
+    /// tell chunks of one message apart from chunks of another when they're
+    /// interleaved on the wire.
+    next_stream_id: AtomicU32,
+    /// Shared with the writer thread: frames queued here are drained and
+    /// written to the socket in priority order.
+    pending_writes: Arc<(Mutex<PendingWrites>, Condvar)>,
+    /// Callbacks invoked on the recv thread for server responses that don't
+    /// correlate to a pending mailbox slot, see [`Connection::on_unprompted`].
+    unprompted_handlers: Arc<Mutex<Vec<UnpromptedHandler>>>,
+}
+
+/// A callback registered via [`Connection::on_unprompted`].
+type UnpromptedHandler = Box<dyn Fn(wandb_internal::ServerResponse) + Send + Sync>;
+
+/// Pending `send_and_recv_message` mailbox slots, keyed by the request's
+/// `control.mailbox_slot` uuid.
+type MailboxHandles = HashMap<String, Sender<Result<wandb_internal::Result, ConnectionError>>>;
+
+/// Adapts the shared, reconnecting socket into a plain [`Read`] for
+/// [`codec::FrameCodec`]: the codec itself knows nothing about `TcpStream`
+/// or reconnection, it just sees a reader that blocks until bytes are
+/// available. A liveness-class I/O error is retried here with a bounded
+/// reconnect before the codec ever observes it.
+///
+/// A clean `Ok(0)` peer close is only worth reconnecting from if it cuts a
+/// frame or a multi-chunk message off partway through (`mid_frame`): that's
+/// a peer that dropped unexpectedly mid-message. An `Ok(0)` between
+/// messages is the normal way a peer that's done talking closes its end,
+/// so it's treated as the end of the stream instead of paying for a
+/// reconnect attempt nothing is waiting on.
+///
+/// Also observes `pending_writes.closed`, the same shutdown flag
+/// [`Connection::close`] sets for the writer/keepalive threads: once a
+/// caller has closed the connection, a disconnect here is expected rather
+/// than something to recover from, so the recv thread stops instead of
+/// reconnecting forever.
+struct ReconnectingReader<'a> {
+    stream: &'a Arc<Mutex<TcpStream>>,
+    peer_addr: SocketAddr,
+    config: &'a ConnectionConfig,
+    pending_writes: &'a Arc<(Mutex<PendingWrites>, Condvar)>,
+    mid_frame: Arc<AtomicBool>,
+}
+
+impl ReconnectingReader<'_> {
+    fn closed(&self) -> bool {
+        self.pending_writes.0.lock().unwrap().closed
+    }
+}
+
+impl Read for ReconnectingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let result = {
+                let stream = self.stream.lock().unwrap();
+                (&*stream).read(buf)
+            };
+            match result {
+                Ok(0) if !self.mid_frame.load(Ordering::Relaxed) => {
+                    // A clean close between frames: the peer is done, not
+                    // dead. Nothing to reconnect for.
+                    return Ok(0);
+                }
+                Ok(0) => {
+                    if self.closed() {
+                        return Ok(0);
+                    }
+                    println!("Peer closed connection mid-message, reconnecting");
+                    if !Connection::reconnect(self.stream, self.peer_addr, self.config) {
+                        return Ok(0);
+                    }
+                }
+                Err(e) if Connection::is_liveness_error(&e) => {
+                    if self.closed() {
+                        return Err(e);
+                    }
+                    println!("Read error ({:?}), reconnecting", e);
+                    if !Connection::reconnect(self.stream, self.peer_addr, self.config) {
+                        return Err(e);
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
 }
 
 impl Connection {
     pub fn clone(&self) -> Self {
         Connection {
-            stream: self.stream.try_clone().unwrap(),
-            handles: self.handles.clone(),
+            stream: Arc::clone(&self.stream),
+            peer_addr: self.peer_addr,
+            config: self.config.clone(),
+            handles: Arc::clone(&self.handles),
+            codec: FrameCodec::new(),
+            next_stream_id: AtomicU32::new(self.next_stream_id.load(Ordering::Relaxed)),
+            pending_writes: Arc::clone(&self.pending_writes),
+            unprompted_handlers: Arc::clone(&self.unprompted_handlers),
         }
     }
 
+    /// Registers a callback invoked on the recv thread for every server
+    /// response other than a `ResultCommunicate` — e.g. status/alert pushes
+    /// the server sends without a prior request. A `ResultCommunicate` is
+    /// never passed to these handlers: one whose mailbox slot has no
+    /// pending `send_and_recv_message` caller, or that carries no control,
+    /// is dropped. Multiple handlers may be registered; each sees every
+    /// such message. Runs on the recv thread, so a slow handler delays
+    /// processing of subsequent messages.
+    pub fn on_unprompted<F>(&mut self, handler: F)
+    where
+        F: Fn(wandb_internal::ServerResponse) + Send + Sync + 'static,
+    {
+        self.unprompted_handlers
+            .lock()
+            .unwrap()
+            .push(Box::new(handler));
+    }
+
+    /// Signals the writer, keepalive, and recv threads to stop instead of
+    /// running for the life of the process: the writer thread drains any
+    /// already-queued frames before exiting, the keepalive thread exits
+    /// after its current sleep without queueing another ping, and `recv`'s
+    /// [`ReconnectingReader`] stops retrying the next time it observes a
+    /// disconnect rather than reconnecting. Safe to call from any clone;
+    /// the threads are shared across all of them via `pending_writes`.
+    pub fn close(&self) {
+        let (lock, cvar) = &*self.pending_writes;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_all();
+    }
+
     pub fn send_and_recv_message(
+        &mut self,
+        message: &mut wandb_internal::Record,
+    ) -> Result<wandb_internal::Result, ConnectionError> {
+        self.send_and_recv_message_with_priority(message, RequestPriority::Normal)
+    }
+
+    pub fn send_and_recv_message_with_priority(
         &mut self,
         // message: &wandb_internal::ServerRequest,
         message: &mut wandb_internal::Record,
-    ) -> wandb_internal::Result {
+        priority: RequestPriority,
+    ) -> Result<wandb_internal::Result, ConnectionError> {
         // todo: generate unique id for this message
         let uuid = generate_run_id(None);
         // message.server_request_type.RecordCommunicate.control.mailbox_slot = uuid.clone();
@@ -59,95 +347,257 @@ impl Connection {
         };
 
         let (sender, receiver) = channel();
-        self.handles.insert(uuid, sender);
-        self.send_message(&message).unwrap();
+        self.handles.lock().unwrap().insert(uuid.clone(), sender);
+        self.send_message_with_priority(&message, priority)?;
 
         println!(">>> Waiting for result...");
-        return receiver.recv().unwrap();
+        let result = match receiver.recv_timeout(self.config.mailbox_timeout) {
+            Ok(result) => result,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(ConnectionError::MailboxTimeout),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(ConnectionError::ChannelClosed),
+        };
+        // The recv thread removes the entry once it delivers a result, but
+        // a timed-out (or disconnected) wait gives up before that happens,
+        // so clean up here too or the slot leaks for the life of the
+        // connection.
+        self.handles.lock().unwrap().remove(&uuid);
+        result
     }
 
-    pub fn send_message(&self, message: &wandb_internal::ServerRequest) -> Result<(), ()> {
-        // marshal the protobuf message
-        let mut buf = Vec::new();
-        message.encode(&mut buf).unwrap();
+    pub fn send_message(&self, message: &wandb_internal::ServerRequest) -> Result<(), ConnectionError> {
+        self.send_message_with_priority(message, RequestPriority::Normal)
+    }
 
-        println!(
-            "Sending message to run {}",
-            self.stream.peer_addr().unwrap()
-        );
-        let mut writer = BufWriter::with_capacity(16384, &self.stream);
+    pub fn send_message_with_priority(
+        &self,
+        message: &wandb_internal::ServerRequest,
+        priority: RequestPriority,
+    ) -> Result<(), ConnectionError> {
+        println!("Sending message to run {}", self.peer_addr);
 
-        let header = Header {
-            magic: b'W',
-            data_length: buf.len() as u32,
-        };
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let frames = FrameCodec::encode_request(message, stream_id);
 
-        // Write the header to the writer
-        writer.write_u8(header.magic).unwrap();
-        writer
-            .write_u32::<LittleEndian>(header.data_length)
-            .unwrap();
-
-        // Write the protobuf to the writer
-        writer.write_all(&buf).unwrap();
-        writer.flush().unwrap();
+        let (lock, cvar) = &*self.pending_writes;
+        let mut pending = lock.lock().unwrap();
+        for frame in frames {
+            pending.push(priority, frame);
+        }
+        drop(pending);
+        cvar.notify_one();
         Ok(())
     }
 
-    pub fn new(stream: TcpStream) -> Self {
+    /// Applies `config`'s read/write timeouts to `stream`.
+    fn configure_stream(stream: &TcpStream, config: &ConnectionConfig) {
+        stream.set_read_timeout(config.read_timeout).unwrap();
+        stream.set_write_timeout(config.read_timeout).unwrap();
+    }
+
+    /// Treats the peer as dead and retries `TcpStream::connect(peer_addr)`
+    /// up to `config.max_reconnect_attempts` times, swapping the shared
+    /// `stream` on success so the reader, writer, and keepalive threads all
+    /// resume on the new socket. Returns whether reconnection succeeded.
+    fn reconnect(
+        stream: &Arc<Mutex<TcpStream>>,
+        peer_addr: SocketAddr,
+        config: &ConnectionConfig,
+    ) -> bool {
+        for attempt in 1..=config.max_reconnect_attempts {
+            println!(
+                "Reconnect attempt {}/{} to {}",
+                attempt, config.max_reconnect_attempts, peer_addr
+            );
+            match TcpStream::connect(peer_addr) {
+                Ok(new_stream) => {
+                    Self::configure_stream(&new_stream, config);
+                    *stream.lock().unwrap() = new_stream;
+                    println!("Reconnected to {}", peer_addr);
+                    return true;
+                }
+                Err(e) => {
+                    println!("Reconnect attempt {} failed: {:?}", attempt, e);
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+            }
+        }
+        false
+    }
+
+    /// Drains `pending_writes` in priority order and writes each frame to
+    /// `stream`, blocking on the condvar whenever the queues are empty.
+    /// Runs on its own thread for the lifetime of the connection so a large
+    /// in-flight send can't delay a latency-sensitive high-priority message
+    /// queued behind it. A write error triggers a bounded reconnect before
+    /// the same frame is retried; the thread only gives up once reconnect
+    /// does.
+    fn run_writer(
+        stream: Arc<Mutex<TcpStream>>,
+        peer_addr: SocketAddr,
+        config: ConnectionConfig,
+        pending_writes: Arc<(Mutex<PendingWrites>, Condvar)>,
+    ) {
+        let (lock, cvar) = &*pending_writes;
+        let mut tick: u64 = 0;
+
+        loop {
+            let mut pending = lock.lock().unwrap();
+            while pending.is_empty() && !pending.closed {
+                pending = cvar.wait(pending).unwrap();
+            }
+            if pending.is_empty() && pending.closed {
+                return;
+            }
+            let frame = pending.pop(tick);
+            drop(pending);
+            tick = tick.wrapping_add(1);
+
+            let Some(frame) = frame else { continue };
+
+            loop {
+                let write_result = {
+                    let mut guard = stream.lock().unwrap();
+                    guard.write_all(&frame).and_then(|_| guard.flush())
+                };
+                match write_result {
+                    Ok(()) => break,
+                    Err(e) => {
+                        println!(
+                            "Writer thread failed to write frame ({:?}), reconnecting",
+                            e
+                        );
+                        if !Self::reconnect(&stream, peer_addr, &config) {
+                            println!("Writer thread giving up after exhausting reconnect attempts");
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every `keepalive_interval`, queues an empty keepalive frame if
+    /// `pending_writes` is empty at that moment, so the peer sees regular
+    /// traffic on an otherwise-quiet link. Frames written earlier in the
+    /// interval are not taken into account: only the queue's state when
+    /// the interval elapses decides whether a ping is sent.
+    fn run_keepalive(pending_writes: Arc<(Mutex<PendingWrites>, Condvar)>, interval: Duration) {
+        loop {
+            std::thread::sleep(interval);
+            let (lock, cvar) = &*pending_writes;
+            let mut pending = lock.lock().unwrap();
+            if pending.closed {
+                return;
+            }
+            if pending.is_empty() {
+                pending.push(
+                    RequestPriority::Low,
+                    FrameCodec::encode_frame(codec::FLAG_KEEPALIVE, codec::KEEPALIVE_STREAM_ID, &[]),
+                );
+                cvar.notify_one();
+            }
+        }
+    }
+
+    pub fn new(stream: TcpStream, config: ConnectionConfig) -> Self {
+        let peer_addr = stream.peer_addr().unwrap();
+        Self::configure_stream(&stream, &config);
+
         let conn = Connection {
-            stream,
-            handles: HashMap::new(),
+            stream: Arc::new(Mutex::new(stream)),
+            peer_addr,
+            config,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            codec: FrameCodec::new(),
+            next_stream_id: AtomicU32::new(codec::KEEPALIVE_STREAM_ID + 1),
+            pending_writes: Arc::new((Mutex::new(PendingWrites::new()), Condvar::new())),
+            unprompted_handlers: Arc::new(Mutex::new(Vec::new())),
         };
 
         // spin up a thread to listen for messages from the server on the connection
         let mut conn_clone = conn.clone();
         std::thread::spawn(move || conn_clone.recv());
 
+        // spin up a dedicated writer thread so a large in-flight send can't
+        // block latency-sensitive messages queued behind it
+        let writer_stream = Arc::clone(&conn.stream);
+        let writer_config = conn.config.clone();
+        let pending_writes = Arc::clone(&conn.pending_writes);
+        std::thread::spawn(move || {
+            Self::run_writer(writer_stream, conn.peer_addr, writer_config, pending_writes)
+        });
+
+        // spin up a keepalive thread to keep the link alive while no real
+        // traffic is queued
+        let keepalive_interval = conn.config.keepalive_interval;
+        let keepalive_writes = Arc::clone(&conn.pending_writes);
+        std::thread::spawn(move || Self::run_keepalive(keepalive_writes, keepalive_interval));
+
         conn
     }
 
-    pub fn recv_message(&self) -> Vec<u8> {
-        // Read the magic byte
-        let mut magic_byte = [0; 1];
-        let bytes_read = std::io::Read::read(&mut &self.stream, &mut magic_byte).unwrap();
-        println!("Read {} bytes", bytes_read);
-        println!("Magic byte: {:?}", magic_byte);
+    /// Whether `e` indicates the peer is unreachable rather than a framing
+    /// error, and is therefore worth a reconnect attempt.
+    fn is_liveness_error(e: &std::io::Error) -> bool {
+        use std::io::ErrorKind::*;
+        matches!(
+            e.kind(),
+            TimedOut | WouldBlock | ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof
+        )
+    }
 
-        if magic_byte != [b'W'] {
-            println!("Magic number is not 'W'");
-            return vec![];
+    /// Drains every pending mailbox sender, delivering `err` to each so a
+    /// blocked `send_and_recv_message` caller unblocks with an error instead
+    /// of hanging forever.
+    fn fail_pending_handles(&mut self, err: ConnectionError) {
+        let mut handles = self.handles.lock().unwrap();
+        println!("Failing {} pending request(s): {}", handles.len(), err);
+        for (_, sender) in handles.drain() {
+            let _ = sender.send(Err(err.clone()));
         }
+    }
 
-        let mut body_length_bytes = [0; 4];
-        std::io::Read::read(&mut &self.stream, &mut body_length_bytes).unwrap();
-        let body_length = u32::from_le_bytes(body_length_bytes);
-        println!("Body length: {}", body_length);
-
-        // Read the body
-        let mut body = vec![0; body_length as usize];
-        std::io::Read::read(&mut &self.stream, &mut body).unwrap();
-        println!("Body: {:?}", body);
-
-        body
+    /// Reads the next reassembled message off the wire via `self.codec`,
+    /// reconnecting transparently underneath it: [`ReconnectingReader`]
+    /// retries a liveness-class I/O error with a bounded reconnect before
+    /// the codec ever sees it, so a dropped connection just looks like a
+    /// slow read to the framing layer.
+    pub fn recv_message(&mut self) -> Result<Vec<u8>, ConnectionError> {
+        let mut reader = ReconnectingReader {
+            stream: &self.stream,
+            peer_addr: self.peer_addr,
+            config: &self.config,
+            pending_writes: &self.pending_writes,
+            mid_frame: self.codec.mid_frame_handle(),
+        };
+        self.codec.read_message(&mut reader)
     }
 
     pub fn recv(&mut self) {
-        println!(
-            "Receiving messages from run {}",
-            self.stream.peer_addr().unwrap()
-        );
+        println!("Receiving messages from run {}", self.peer_addr);
         loop {
             println!("Waiting for message...");
-            let msg = self.recv_message();
-            if msg.len() == 0 {
-                println!("Connection closed");
-                break;
-            }
-            let proto_message = wandb_internal::ServerResponse::decode(msg.as_slice()).unwrap();
+            let msg = match self.recv_message() {
+                Ok(msg) => msg,
+                Err(err) => {
+                    println!("Connection closed: {}", err);
+                    self.fail_pending_handles(err);
+                    break;
+                }
+            };
+
+            let proto_message = match wandb_internal::ServerResponse::decode(msg.as_slice()) {
+                Ok(proto_message) => proto_message,
+                Err(e) => {
+                    let err = ConnectionError::from(e);
+                    println!("Failed to decode message: {}", err);
+                    self.fail_pending_handles(err);
+                    break;
+                }
+            };
             println!("Received message: {:?}", proto_message);
 
-            match proto_message.server_response_type {
+            match proto_message.server_response_type.clone() {
                 Some(wandb_internal::server_response::ServerResponseType::ResultCommunicate(
                     result,
                 )) => {
@@ -158,10 +608,10 @@ impl Connection {
                     if let Some(control) = &result.control {
                         let mailbox_slot = &control.mailbox_slot;
                         println!("Mailbox slot: {}", mailbox_slot);
-                        if let Some(sender) = self.handles.get(mailbox_slot) {
+                        if let Some(sender) = self.handles.lock().unwrap().remove(mailbox_slot) {
                             println!("Sending result to sender {:?}", sender);
                             // todo: use the result type of the result_communicate
-                            sender.send(result.clone()).expect("Failed to send result")
+                            let _ = sender.send(Ok(result.clone()));
                         } else {
                             println!("Failed to send result to sender");
                         }
@@ -170,7 +620,14 @@ impl Connection {
                     }
                 }
                 Some(_) => {
-                    println!("Received message with unknown type");
+                    // Not correlated to a pending mailbox slot: a message
+                    // the server pushed on its own, e.g. a status update or
+                    // alert. Hand it to whoever subscribed instead of
+                    // dropping it.
+                    println!("Dispatching unprompted server response");
+                    for handler in self.unprompted_handlers.lock().unwrap().iter() {
+                        handler(proto_message.clone());
+                    }
                 }
                 None => {
                     println!("Received message without type")
@@ -180,3 +637,456 @@ impl Connection {
         }
     }
 }
+
+/// Wire framing, independent of `TcpStream`: everything here operates over
+/// any `Read`/`Write`, so it can be exercised against an in-memory buffer in
+/// tests as well as against the real socket in [`Connection`].
+mod codec {
+    use super::{wandb_internal, ConnectionError};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use prost::Message;
+    use std::collections::{HashMap, VecDeque};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Maximum number of bytes of protobuf payload carried by a single
+    /// frame. Payloads larger than this are split across multiple chunks,
+    /// see [`FrameCodec::encode_frames`].
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    /// Set on every frame except the last one in a stream.
+    const FLAG_CONTINUATION: u8 = 0b01;
+    /// Set on the final frame of a stream; the reassembled body is complete
+    /// once a frame with this flag is received.
+    const FLAG_EOS: u8 = 0b10;
+    /// Marks a frame as an idle-link keepalive ping rather than part of a
+    /// message stream; the receiver discards these without reassembling
+    /// them into a stream's body.
+    pub(crate) const FLAG_KEEPALIVE: u8 = 0b100;
+    /// Reserved stream id used for keepalive frames, which never carry a
+    /// body to reassemble.
+    pub(crate) const KEEPALIVE_STREAM_ID: u32 = 0;
+
+    #[repr(C)]
+    struct Header {
+        magic: u8,
+        flags: u8,
+        stream_id: u32,
+        data_length: u32,
+    }
+
+    /// A growable byte buffer that frames are read into. Bytes are pushed
+    /// onto the back as they arrive off the reader and pulled off the front
+    /// once a full field (length prefix, body, ...) is available, so a
+    /// `read()` that returns fewer bytes than requested (or a length prefix
+    /// split across two `read()` calls) never loses data between calls to
+    /// [`FrameCodec::read_message`].
+    struct RecvBuffer {
+        bytes: VecDeque<u8>,
+    }
+
+    impl RecvBuffer {
+        fn new() -> Self {
+            RecvBuffer {
+                bytes: VecDeque::new(),
+            }
+        }
+
+        /// Appends newly-read bytes to the buffer.
+        fn extend(&mut self, data: &[u8]) {
+            self.bytes.extend(data.iter().copied());
+        }
+
+        /// Removes and returns exactly `n` bytes from the front of the
+        /// buffer, or `None` (leaving the buffer untouched) if fewer than
+        /// `n` bytes are currently buffered.
+        fn take_exact(&mut self, n: usize) -> Option<Vec<u8>> {
+            if self.bytes.len() < n {
+                return None;
+            }
+            Some(self.bytes.drain(..n).collect())
+        }
+
+        /// Whether no bytes are currently buffered.
+        fn is_empty(&self) -> bool {
+            self.bytes.is_empty()
+        }
+    }
+
+    /// A single length-prefixed frame read off the wire, before stream
+    /// reassembly.
+    struct Frame {
+        flags: u8,
+        stream_id: u32,
+        body: Vec<u8>,
+    }
+
+    /// Transport-agnostic wire framing and stream reassembly. Holds the
+    /// state a partially-read frame or partially-reassembled stream needs
+    /// to survive across `read()` calls, independent of where those bytes
+    /// actually come from — a `TcpStream` in [`super::Connection`], or an
+    /// in-memory buffer in this module's tests.
+    pub(crate) struct FrameCodec {
+        recv_buffer: RecvBuffer,
+        /// Partially-reassembled bodies for in-flight streams, keyed by
+        /// stream id, until their `FLAG_EOS` frame arrives.
+        stream_buffers: HashMap<u32, Vec<u8>>,
+        /// True whenever some frame or stream is only partially read: set
+        /// the moment a frame's magic byte arrives, and only cleared once
+        /// every stream reassembling in `stream_buffers` has seen its
+        /// `FLAG_EOS` frame. Shared with the transport layer via
+        /// [`FrameCodec::mid_frame_handle`] so it can tell a disconnect
+        /// that happens cleanly between messages from one that cuts a
+        /// frame or a multi-chunk message off partway through.
+        mid_frame: Arc<AtomicBool>,
+    }
+
+    impl FrameCodec {
+        pub(crate) fn new() -> Self {
+            FrameCodec {
+                recv_buffer: RecvBuffer::new(),
+                stream_buffers: HashMap::new(),
+                mid_frame: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        /// A handle the transport layer can poll to tell a disconnect mid
+        /// frame or mid-message apart from one that happens cleanly between
+        /// messages.
+        pub(crate) fn mid_frame_handle(&self) -> Arc<AtomicBool> {
+            Arc::clone(&self.mid_frame)
+        }
+
+        /// Reads from `reader` into `self.recv_buffer` until at least `n`
+        /// bytes are available, then returns exactly those `n` bytes.
+        fn fill_exact<R: Read>(&mut self, reader: &mut R, n: usize) -> Result<Vec<u8>, ConnectionError> {
+            let mut scratch = [0u8; 4096];
+            loop {
+                if let Some(bytes) = self.recv_buffer.take_exact(n) {
+                    return Ok(bytes);
+                }
+                match reader.read(&mut scratch) {
+                    Ok(0) => {
+                        return Err(if self.recv_buffer.is_empty() {
+                            ConnectionError::PeerClosed
+                        } else {
+                            ConnectionError::ShortRead
+                        });
+                    }
+                    Ok(read) => {
+                        self.recv_buffer.extend(&scratch[..read]);
+                    }
+                    Err(e) => return Err(ConnectionError::from(e)),
+                }
+            }
+        }
+
+        /// Reads one length-prefixed frame off `reader`.
+        fn read_frame<R: Read>(&mut self, reader: &mut R) -> Result<Frame, ConnectionError> {
+            let magic = self.fill_exact(reader, 1)?[0];
+            // A frame has started arriving: a disconnect from here on cuts
+            // it off partway through, rather than landing cleanly between
+            // frames. `read_message` is responsible for clearing this once
+            // it knows no stream is left incomplete.
+            self.mid_frame.store(true, Ordering::Relaxed);
+            if magic != b'W' {
+                return Err(ConnectionError::BadMagic { got: magic });
+            }
+
+            // The frame has started, so running out of input from here on is
+            // a `ShortRead` even when the buffer is empty at a field boundary.
+            let mid = |e: ConnectionError| match e {
+                ConnectionError::PeerClosed => ConnectionError::ShortRead,
+                e => e,
+            };
+
+            let flags = self.fill_exact(reader, 1).map_err(mid)?[0];
+            let stream_id =
+                u32::from_le_bytes(self.fill_exact(reader, 4).map_err(mid)?.try_into().unwrap());
+
+            // The 4-byte little-endian body length may itself be split
+            // across multiple `read()` calls.
+            let body_length =
+                u32::from_le_bytes(self.fill_exact(reader, 4).map_err(mid)?.try_into().unwrap())
+                    as usize;
+
+            // Read the body, accumulating across as many reads as it takes.
+            let body = self.fill_exact(reader, body_length).map_err(mid)?;
+
+            Ok(Frame {
+                flags,
+                stream_id,
+                body,
+            })
+        }
+
+        /// Reads frames off `reader` until one completes a stream (its
+        /// `FLAG_EOS` frame arrives), reassembling chunks keyed by stream id
+        /// so concurrent streams can interleave. Returns the fully
+        /// reassembled body.
+        pub(crate) fn read_message<R: Read>(&mut self, reader: &mut R) -> Result<Vec<u8>, ConnectionError> {
+            loop {
+                let frame = self.read_frame(reader)?;
+
+                if frame.flags & FLAG_KEEPALIVE != 0 {
+                    // A keepalive carries no stream data, so it doesn't
+                    // leave anything mid-assembly on its own; whether we're
+                    // still mid-frame depends only on any other stream
+                    // that's already in flight.
+                    self.recompute_mid_frame();
+                    continue;
+                }
+
+                let body = self.stream_buffers.entry(frame.stream_id).or_default();
+                body.extend_from_slice(&frame.body);
+
+                if frame.flags & FLAG_EOS != 0 {
+                    let body = self.stream_buffers.remove(&frame.stream_id).unwrap();
+                    // Done with this stream; still mid-frame only if some
+                    // other stream is left incomplete.
+                    self.recompute_mid_frame();
+                    return Ok(body);
+                }
+            }
+        }
+
+        /// Sets `mid_frame` to whether any stream is still mid-assembly.
+        fn recompute_mid_frame(&self) {
+            self.mid_frame
+                .store(!self.stream_buffers.is_empty(), Ordering::Relaxed);
+        }
+
+        /// Splits `buf` into `CHUNK_SIZE` frames tagged with `stream_id` so
+        /// the receiver can reassemble it. Full-size chunks are always
+        /// marked `FLAG_CONTINUATION`; the remainder (which is empty when
+        /// `buf` is empty, or an exact multiple of `CHUNK_SIZE`) is sent as
+        /// a final `FLAG_EOS` frame, so an exact-multiple payload ends with
+        /// an EOS frame carrying no body and an empty payload produces
+        /// exactly one EOS frame.
+        pub(crate) fn encode_frames(stream_id: u32, buf: &[u8]) -> Vec<Vec<u8>> {
+            let mut frames = Vec::new();
+
+            let mut offset = 0;
+            while offset + CHUNK_SIZE <= buf.len() {
+                frames.push(Self::encode_frame(
+                    FLAG_CONTINUATION,
+                    stream_id,
+                    &buf[offset..offset + CHUNK_SIZE],
+                ));
+                offset += CHUNK_SIZE;
+            }
+            frames.push(Self::encode_frame(FLAG_EOS, stream_id, &buf[offset..]));
+
+            frames
+        }
+
+        /// Encodes a [`wandb_internal::ServerRequest`] as its own
+        /// (possibly multi-frame) stream.
+        pub(crate) fn encode_request(message: &wandb_internal::ServerRequest, stream_id: u32) -> Vec<Vec<u8>> {
+            let mut buf = Vec::new();
+            message.encode(&mut buf).unwrap();
+            Self::encode_frames(stream_id, &buf)
+        }
+
+        pub(crate) fn encode_frame(flags: u8, stream_id: u32, chunk: &[u8]) -> Vec<u8> {
+            let mut bytes = Vec::with_capacity(10 + chunk.len());
+            Self::write_frame(&mut bytes, flags, stream_id, chunk);
+            bytes
+        }
+
+        fn write_frame<W: WriteBytesExt>(writer: &mut W, flags: u8, stream_id: u32, chunk: &[u8]) {
+            let header = Header {
+                magic: b'W',
+                flags,
+                stream_id,
+                data_length: chunk.len() as u32,
+            };
+
+            writer.write_u8(header.magic).unwrap();
+            writer.write_u8(header.flags).unwrap();
+            writer
+                .write_u32::<LittleEndian>(header.stream_id)
+                .unwrap();
+            writer
+                .write_u32::<LittleEndian>(header.data_length)
+                .unwrap();
+            writer.write_all(chunk).unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn sample_record(mailbox_slot: &str) -> wandb_internal::Record {
+            wandb_internal::Record {
+                control: Some(wandb_internal::Control {
+                    mailbox_slot: mailbox_slot.to_string(),
+                    req_resp: true,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        }
+
+        fn encode_as_frames(record: &wandb_internal::Record) -> Vec<u8> {
+            let mut body = Vec::new();
+            record.encode(&mut body).unwrap();
+
+            let mut wire = Vec::new();
+            for frame in FrameCodec::encode_frames(0, &body) {
+                wire.extend_from_slice(&frame);
+            }
+            wire
+        }
+
+        #[test]
+        fn round_trips_a_record_through_an_in_memory_buffer() {
+            let record = sample_record("test-slot");
+            let wire = encode_as_frames(&record);
+
+            let mut codec = FrameCodec::new();
+            let decoded_body = codec.read_message(&mut wire.as_slice()).unwrap();
+            let decoded = wandb_internal::Record::decode(decoded_body.as_slice()).unwrap();
+
+            assert_eq!(record, decoded);
+        }
+
+        /// Hands `wire` back across two `read()` calls split at `split`, to
+        /// simulate a socket read that delivers the frame in pieces instead
+        /// of all at once.
+        struct SplitReader {
+            chunks: VecDeque<Vec<u8>>,
+        }
+
+        impl SplitReader {
+            fn new(wire: &[u8], split: usize) -> Self {
+                let mut chunks = VecDeque::new();
+                if split == 0 || split == wire.len() {
+                    chunks.push_back(wire.to_vec());
+                } else {
+                    chunks.push_back(wire[..split].to_vec());
+                    chunks.push_back(wire[split..].to_vec());
+                }
+                SplitReader { chunks }
+            }
+        }
+
+        impl Read for SplitReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let Some(chunk) = self.chunks.pop_front() else {
+                    return Ok(0);
+                };
+                let n = chunk.len().min(buf.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    self.chunks.push_front(chunk[n..].to_vec());
+                }
+                Ok(n)
+            }
+        }
+
+        #[test]
+        fn reassembles_correctly_no_matter_where_the_read_is_split() {
+            let record = sample_record("split-slot");
+            let wire = encode_as_frames(&record);
+
+            for split in 0..=wire.len() {
+                let mut reader = SplitReader::new(&wire, split);
+                let mut codec = FrameCodec::new();
+                let decoded_body = codec
+                    .read_message(&mut reader)
+                    .unwrap_or_else(|e| panic!("split at {} failed: {}", split, e));
+                let decoded = wandb_internal::Record::decode(decoded_body.as_slice()).unwrap();
+
+                assert_eq!(record, decoded, "split at {}", split);
+            }
+        }
+
+        /// Reads the little-endian body-length field out of an encoded
+        /// frame's header, so tests can check a frame's body size without
+        /// decoding it.
+        fn frame_body_len(frame: &[u8]) -> usize {
+            u32::from_le_bytes(frame[6..10].try_into().unwrap()) as usize
+        }
+
+        #[test]
+        fn encode_frames_handles_every_chunk_boundary() {
+            // One byte short of a full chunk: the whole payload fits in a
+            // single EOS frame.
+            let buf = vec![0xAB; CHUNK_SIZE - 1];
+            let frames = FrameCodec::encode_frames(0, &buf);
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0][1], FLAG_EOS);
+            assert_eq!(frame_body_len(&frames[0]), CHUNK_SIZE - 1);
+
+            // Exactly one chunk: a full CONTINUATION frame, then an
+            // empty-body EOS frame.
+            let buf = vec![0xAB; CHUNK_SIZE];
+            let frames = FrameCodec::encode_frames(0, &buf);
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0][1], FLAG_CONTINUATION);
+            assert_eq!(frame_body_len(&frames[0]), CHUNK_SIZE);
+            assert_eq!(frames[1][1], FLAG_EOS);
+            assert_eq!(frame_body_len(&frames[1]), 0);
+
+            // One byte over a full chunk: a full CONTINUATION frame, then a
+            // one-byte EOS frame.
+            let buf = vec![0xAB; CHUNK_SIZE + 1];
+            let frames = FrameCodec::encode_frames(0, &buf);
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0][1], FLAG_CONTINUATION);
+            assert_eq!(frame_body_len(&frames[0]), CHUNK_SIZE);
+            assert_eq!(frames[1][1], FLAG_EOS);
+            assert_eq!(frame_body_len(&frames[1]), 1);
+
+            // Empty payload: a single EOS frame with no body at all.
+            let frames = FrameCodec::encode_frames(0, &[]);
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0][1], FLAG_EOS);
+            assert_eq!(frame_body_len(&frames[0]), 0);
+        }
+
+        #[test]
+        fn reassembles_payloads_of_every_chunk_boundary_size() {
+            for len in [0, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1] {
+                let buf: Vec<u8> = (0..len).map(|i| (i % 256) as u8).collect();
+
+                let mut wire = Vec::new();
+                for frame in FrameCodec::encode_frames(0, &buf) {
+                    wire.extend_from_slice(&frame);
+                }
+
+                let mut codec = FrameCodec::new();
+                let decoded = codec
+                    .read_message(&mut wire.as_slice())
+                    .unwrap_or_else(|e| panic!("payload length {} failed: {}", len, e));
+
+                assert_eq!(decoded, buf, "payload length {}", len);
+            }
+        }
+
+        #[test]
+        fn mid_frame_stays_set_between_a_continuation_frame_and_its_eos_frame() {
+            // A payload over CHUNK_SIZE always splits into a CONTINUATION
+            // frame followed by an EOS frame; a disconnect in the gap
+            // between them is still partway through the message, not a
+            // clean between-messages close.
+            let buf = vec![0xAB; CHUNK_SIZE + 1];
+            let frames = FrameCodec::encode_frames(0, &buf);
+            assert_eq!(frames.len(), 2);
+
+            let mut codec = FrameCodec::new();
+            let mid_frame = codec.mid_frame_handle();
+            assert!(!mid_frame.load(Ordering::Relaxed));
+
+            // Read only the CONTINUATION frame; the EOS frame hasn't
+            // arrived yet.
+            let mut reader = frames[0].as_slice();
+            let err = codec.read_message(&mut reader).unwrap_err();
+            assert!(matches!(err, ConnectionError::PeerClosed));
+            assert!(mid_frame.load(Ordering::Relaxed));
+        }
+    }
+}